@@ -24,7 +24,7 @@ pub struct DirInfo {
   pub uri: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct Checksums {
   #[serde(default)]
   pub md5: String,
@@ -34,7 +34,7 @@ pub struct Checksums {
   pub sha256: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct FileInfo {
   pub checksums: Checksums,
   pub created: String,
@@ -68,13 +68,13 @@ pub struct FileInfo {
 
 #[derive(Deserialize, Debug)]
 pub struct RtError {
-  message: String,
-  status: i16,
+  pub(crate) message: String,
+  pub(crate) status: i16,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct RtErrors {
-  errors: Vec<RtError>,
+  pub(crate) errors: Vec<RtError>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -121,10 +121,37 @@ impl Artifactory {
       .header("Authorization", &self._auth)
       .header("Range", format!("bytes={}-{}", offset, offset+(size as u64)))
       .send()?;
-    resp.copy_to(buf).expect("could not copy file data to buffer");
+    resp.copy_to(buf)?;
     Ok(resp)
   }
 
+  /// Deploys `data` as the full contents of `path` (`{repo}/{path}`) via
+  /// the Artifactory deploy API (a plain HTTP PUT). Errors on any non-2xx
+  /// status, since `send()` alone only fails on transport errors.
+  pub fn put_file(&self, path: &String, data: Vec<u8>) -> reqwest::Result<reqwest::Response> {
+    let url = format!("{}/{}", self.host, path);
+    self
+      ._client
+      .put(&url)
+      .header("Authorization", &self._auth)
+      .body(data)
+      .send()?
+      .error_for_status()
+  }
+
+  /// Deletes `path` (`{repo}/{path}`) via the Artifactory deploy API.
+  /// Errors on any non-2xx status, since `send()` alone only fails on
+  /// transport errors.
+  pub fn delete_path(&self, path: &String) -> reqwest::Result<reqwest::Response> {
+    let url = format!("{}/{}", self.host, path);
+    self
+      ._client
+      .delete(&url)
+      .header("Authorization", &self._auth)
+      .send()?
+      .error_for_status()
+  }
+
   fn _api(&self, endpoint: &str) -> reqwest::Result<reqwest::Response> {
     let url = format!("{}/api/{}", self.host, endpoint);
     self._get(&url)
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow;
+use dirs;
+
+pub const BLOCK_SIZE: u64 = 1024 * 1024;
+const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+#[derive(Clone, Eq, PartialEq, Hash)]
+struct BlockKey {
+  digest: String,
+  block_index: u64,
+}
+
+#[derive(Clone)]
+struct BlockMeta {
+  atime: u64,
+  size: u64,
+}
+
+pub struct BlockCache {
+  dir: PathBuf,
+  cap: u64,
+  index: Mutex<HashMap<BlockKey, BlockMeta>>,
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+fn block_key(download_uri: &str, offset: u64) -> BlockKey {
+  BlockKey {
+    digest: hash_name(download_uri),
+    block_index: offset / BLOCK_SIZE,
+  }
+}
+
+impl BlockCache {
+  pub fn new() -> Self {
+    let dir = dirs::data_dir()
+      .unwrap_or_else(std::env::temp_dir)
+      .join("rtfs")
+      .join("cache");
+    fs::create_dir_all(&dir).ok();
+    let cap = std::env::var("rtfs_cache_size")
+      .ok()
+      .and_then(|v| v.parse::<u64>().ok())
+      .unwrap_or(DEFAULT_CACHE_SIZE);
+    let index = Self::scan_existing_blocks(&dir);
+    Self {
+      dir,
+      cap,
+      index: Mutex::new(index),
+    }
+  }
+
+  /// Rebuilds the in-memory LRU index from whatever `*.blk` files are
+  /// already on disk, keyed off the `{digest}-{block_index}.blk` name
+  /// `block_path` writes. Without this, blocks left over from a previous
+  /// run are invisible to `evict_if_needed` until something happens to
+  /// re-touch them, and `rtfs_cache_size` stops being enforced across
+  /// restarts. A file's mtime stands in for its last-read time, since we
+  /// have no record of the real one.
+  fn scan_existing_blocks(dir: &PathBuf) -> HashMap<BlockKey, BlockMeta> {
+    let mut index = HashMap::new();
+    let entries = match fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(_) => return index,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+      let path = entry.path();
+      if path.extension().and_then(|ext| ext.to_str()) != Some("blk") {
+        continue;
+      }
+      let stem = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => stem,
+        None => continue,
+      };
+      let dash = match stem.rfind('-') {
+        Some(i) => i,
+        None => continue,
+      };
+      let block_index: u64 = match stem[dash + 1..].parse() {
+        Ok(v) => v,
+        Err(_) => continue,
+      };
+      let meta = match entry.metadata() {
+        Ok(m) => m,
+        Err(_) => continue,
+      };
+      let atime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+      index.insert(
+        BlockKey {
+          digest: stem[..dash].to_string(),
+          block_index,
+        },
+        BlockMeta {
+          atime,
+          size: meta.len(),
+        },
+      );
+    }
+    index
+  }
+
+  /// Serves `offset..offset+size` of `download_uri`, fetching any missing
+  /// blocks through `fetch(block_offset, block_size)` and persisting them
+  /// to disk. `stamp` identifies the artifact's current version (its
+  /// `last_updated`); a change in `stamp` invalidates the file's blocks.
+  pub fn read(
+    &self,
+    download_uri: &str,
+    stamp: &str,
+    offset: u64,
+    size: u32,
+    fetch: &dyn Fn(u64, u32) -> anyhow::Result<Vec<u8>>,
+  ) -> anyhow::Result<Vec<u8>> {
+    if self.stamp_changed(download_uri, stamp) {
+      self.invalidate(download_uri);
+    }
+
+    let end = offset + size as u64;
+    let mut out = Vec::with_capacity(size as usize);
+    let mut block_start = (offset / BLOCK_SIZE) * BLOCK_SIZE;
+    while block_start < end {
+      let block = self.read_block(download_uri, stamp, block_start, fetch)?;
+      let usable = (block.len() as u64).min(BLOCK_SIZE);
+      let lo = offset.saturating_sub(block_start).min(usable) as usize;
+      let hi = (end.saturating_sub(block_start)).min(usable) as usize;
+      out.extend_from_slice(&block[lo..hi]);
+      block_start += BLOCK_SIZE;
+    }
+    Ok(out)
+  }
+
+  fn stamp_changed(&self, download_uri: &str, stamp: &str) -> bool {
+    let path = self.stamp_path(download_uri);
+    match fs::read_to_string(&path) {
+      Ok(existing) => existing != stamp,
+      Err(_) => false,
+    }
+  }
+
+  fn read_block(
+    &self,
+    download_uri: &str,
+    stamp: &str,
+    block_start: u64,
+    fetch: &dyn Fn(u64, u32) -> anyhow::Result<Vec<u8>>,
+  ) -> anyhow::Result<Vec<u8>> {
+    let key = block_key(download_uri, block_start);
+    let path = self.block_path(&key);
+
+    if let Ok(data) = fs::read(&path) {
+      self.touch(key, data.len() as u64);
+      return Ok(data);
+    }
+
+    let mut data = fetch(block_start, BLOCK_SIZE as u32)?;
+    // `Artifactory::read_file` sends an inclusive `Range` header, so the
+    // server returns one byte more than asked for; truncate to the
+    // nominal block size so stored blocks - and therefore every splice
+    // in `read()` - stay aligned.
+    data.truncate(BLOCK_SIZE as usize);
+    fs::create_dir_all(&self.dir).ok();
+    // `fs::write` truncates-then-writes in place, so a concurrent reader
+    // of the same block (the 9P server puts every connection on its own
+    // thread over one shared cache) could `fs::read` a torn file while
+    // we're mid-write. Write to a uniquely-named temp file first and
+    // `fs::rename` it into place, which is atomic on the same
+    // filesystem, so any other reader only ever sees the block missing
+    // or complete, never partial.
+    let tmp_path = self.dir.join(format!(
+      "{}-{}.blk.tmp-{:?}-{}",
+      key.digest,
+      key.block_index,
+      std::thread::current().id(),
+      now()
+    ));
+    fs::write(&tmp_path, &data)?;
+    fs::rename(&tmp_path, &path)?;
+    fs::write(self.stamp_path(download_uri), stamp).ok();
+    self.touch(key, data.len() as u64);
+    self.evict_if_needed();
+    Ok(data)
+  }
+
+  fn touch(&self, key: BlockKey, size: u64) {
+    let mut index = self.index.lock().expect("could not lock cache index");
+    index.insert(
+      key,
+      BlockMeta {
+        atime: now(),
+        size,
+      },
+    );
+  }
+
+  fn evict_if_needed(&self) {
+    let mut index = self.index.lock().expect("could not lock cache index");
+    let mut total: u64 = index.values().map(|m| m.size).sum();
+    if total <= self.cap {
+      return;
+    }
+    let mut entries: Vec<(BlockKey, BlockMeta)> =
+      index.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    entries.sort_by_key(|(_, m)| m.atime);
+    for (key, meta) in entries {
+      if total <= self.cap {
+        break;
+      }
+      fs::remove_file(self.block_path(&key)).ok();
+      index.remove(&key);
+      total = total.saturating_sub(meta.size);
+    }
+  }
+
+  fn invalidate(&self, download_uri: &str) {
+    let digest = hash_name(download_uri);
+    let mut index = self.index.lock().expect("could not lock cache index");
+    let stale: Vec<BlockKey> = index
+      .keys()
+      .filter(|k| k.digest == digest)
+      .cloned()
+      .collect();
+    for key in stale {
+      fs::remove_file(self.block_path(&key)).ok();
+      index.remove(&key);
+    }
+  }
+
+  fn block_path(&self, key: &BlockKey) -> PathBuf {
+    self.dir.join(format!("{}-{}.blk", key.digest, key.block_index))
+  }
+
+  fn stamp_path(&self, download_uri: &str) -> PathBuf {
+    self.dir.join(format!("{}.stamp", hash_name(download_uri)))
+  }
+}
+
+/// Cheap, dependency-free FNV-1a hash, also reused by the 9P frontend to
+/// derive stable Qid paths from artifact paths.
+pub(crate) fn fnv64(s: &str) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for byte in s.bytes() {
+    hash ^= byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
+}
+
+/// Cheap, dependency-free hash of a URI into a filesystem-safe name.
+fn hash_name(s: &str) -> String {
+  format!("{:016x}", fnv64(s))
+}
@@ -0,0 +1,66 @@
+use std::io::Write;
+
+use crate::cache::BLOCK_SIZE;
+use crate::tar;
+use crate::tree::{timestamp_to_timespec, NodeKind, RepoTree};
+
+/// Streams `path` (and, if it's a directory, everything beneath it) out
+/// of `tree` as a POSIX tar archive, fetching file bodies in
+/// `BLOCK_SIZE` chunks through `RepoTree::read` so memory use stays
+/// bounded regardless of how large the subtree is.
+pub fn export(tree: &RepoTree, path: &str, out: &mut impl Write) -> anyhow::Result<()> {
+  let entries = tree
+    .walk(path)
+    .map_err(|e| anyhow::anyhow!("could not walk {}: errno {}", path, e.to_errno()))?;
+
+  for (entry_path, attr) in entries {
+    let name = tar_name(&entry_path, attr.kind);
+    let mtime = timestamp_to_timespec(&attr.last_modified)
+      .map(|ts| ts.sec)
+      .unwrap_or(0);
+    let is_dir = attr.kind == NodeKind::Directory;
+    tar::write_header(out, &name, attr.size, mtime, 0o644, is_dir)?;
+
+    if !is_dir {
+      let mut offset = 0u64;
+      while offset < attr.size {
+        let chunk = (attr.size - offset).min(BLOCK_SIZE) as u32;
+        let data = tree.read(&entry_path, offset, chunk).map_err(|e| {
+          anyhow::anyhow!("could not read {} at {}: errno {}", entry_path, offset, e.to_errno())
+        })?;
+        if data.is_empty() {
+          break;
+        }
+        out.write_all(&data)?;
+        offset += data.len() as u64;
+      }
+      // If the artifact shrank out from under us mid-export, `offset`
+      // won't reach `attr.size`: padding against the declared size here
+      // would leave the header's size field lying about what we
+      // actually wrote and misalign every tar header after it. Fail the
+      // export instead of silently emitting a corrupt archive.
+      if offset != attr.size {
+        anyhow::bail!(
+          "{} changed size during export: expected {} bytes, only read {}",
+          entry_path,
+          attr.size,
+          offset
+        );
+      }
+      tar::write_padding(out, offset)?;
+    }
+  }
+
+  tar::write_end(out)?;
+  Ok(())
+}
+
+/// tar paths are relative and unrooted; directories carry a trailing
+/// `/` by convention so extractors recreate them even when empty.
+fn tar_name(path: &str, kind: NodeKind) -> String {
+  let trimmed = path.trim_start_matches('/');
+  match kind {
+    NodeKind::Directory if !trimmed.is_empty() => format!("{}/", trimmed),
+    _ => trimmed.to_string(),
+  }
+}
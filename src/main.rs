@@ -12,7 +12,12 @@ extern crate serde_derive;
 extern crate log;
 
 mod artifactory;
+mod cache;
+mod export;
+mod ninep;
 mod rtfs;
+mod tar;
+mod tree;
 
 #[derive(Deserialize, Debug)]
 struct Env {
@@ -37,14 +42,20 @@ impl log::Log for ConsoleLogger {
 
 static LOGGER: ConsoleLogger = ConsoleLogger;
 
+fn usage() -> ! {
+  println!("Usage: rtfs <repo-name> <mount-point>");
+  println!("       rtfs serve-9p <repo-name> <listen-addr>");
+  println!("       rtfs export <repo-name>/<path> <output.tar>");
+  process::exit(1);
+}
+
 fn main() -> Result<(), reqwest::Error> {
   log::set_logger(&LOGGER).unwrap();
   log::set_max_level(log::LevelFilter::Debug);
   dotenv::dotenv().ok();
   let args: Vec<String> = env::args().collect();
-  if args.len() != 3 {
-    println!("Usage: rtfs <repo-name> <mount-point>");
-    process::exit(1);
+  if args.len() != 3 && args.len() != 4 {
+    usage();
   }
   let env = envy::from_env::<Env>().unwrap_or_else(|e| {
     println!("Could not read environment or .env: {}", e);
@@ -56,6 +67,34 @@ fn main() -> Result<(), reqwest::Error> {
     &env.rtfs_user,
     &env.rtfs_token,
   ));
+
+  if args.len() == 4 && args[1] == "serve-9p" {
+    let server = ninep::Server::new(tree::RepoTree::new(rt, args[2].clone()));
+    server.serve(&args[3]).unwrap_or_else(|e| {
+      println!("9P server failed: {}", e);
+      process::exit(1);
+    });
+    return Ok(());
+  }
+  if args.len() == 4 && args[1] == "export" {
+    let mut parts = args[2].splitn(2, '/');
+    let repo = parts.next().unwrap_or(&args[2]);
+    let path = parts.next().unwrap_or("");
+    let tree = tree::RepoTree::new(rt, repo.to_string());
+    let mut out = std::fs::File::create(&args[3]).unwrap_or_else(|e| {
+      println!("could not create {}: {}", args[3], e);
+      process::exit(1);
+    });
+    export::export(&tree, &format!("/{}", path), &mut out).unwrap_or_else(|e| {
+      println!("export failed: {}", e);
+      process::exit(1);
+    });
+    return Ok(());
+  }
+  if args.len() != 3 {
+    usage();
+  }
+
   let filesystem = rtfs::RtFS::new(rt, args[1].clone());
   let fuse_args: Vec<&OsStr> =
     vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
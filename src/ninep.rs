@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::tree::{NodeKind, RepoTree};
+
+// 9P2000.L message types (T = request, R = reply).
+const TVERSION: u8 = 100;
+const RVERSION: u8 = 101;
+const TATTACH: u8 = 104;
+const RATTACH: u8 = 105;
+const RLERROR: u8 = 7;
+const TWALK: u8 = 110;
+const RWALK: u8 = 111;
+const TLOPEN: u8 = 12;
+const RLOPEN: u8 = 13;
+const TREADDIR: u8 = 40;
+const RREADDIR: u8 = 41;
+const TREAD: u8 = 116;
+const RREAD: u8 = 117;
+const TGETATTR: u8 = 24;
+const RGETATTR: u8 = 25;
+const TCLUNK: u8 = 120;
+const RCLUNK: u8 = 121;
+
+const QTDIR: u8 = 0x80;
+const QTFILE: u8 = 0x00;
+
+const MSIZE: u32 = 64 * 1024;
+
+// getattr request mask bit for "all the fields we actually fill in":
+// mode, nlink, uid, gid, rdev, ino, size and blocks (the times are zeroed).
+const GETATTR_BASIC: u64 = 0x0000071f;
+
+struct Qid {
+  kind: u8,
+  version: u32,
+  path: u64,
+}
+
+impl Qid {
+  fn for_path(path: &str, node_kind: NodeKind) -> Self {
+    Qid {
+      kind: match node_kind {
+        NodeKind::Directory => QTDIR,
+        NodeKind::File => QTFILE,
+      },
+      version: 0,
+      path: crate::cache::fnv64(path),
+    }
+  }
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    buf.push(self.kind);
+    buf.extend_from_slice(&self.version.to_le_bytes());
+    buf.extend_from_slice(&self.path.to_le_bytes());
+  }
+}
+
+/// Per-connection state for a single fid: the tree-relative path it was
+/// walked to, and (for directories) the encoded `Rreaddir` payload a
+/// `Treaddir` should be served from, built lazily on first read.
+struct Fid {
+  path: String,
+  kind: NodeKind,
+  dir_buf: Option<Vec<u8>>,
+}
+
+/// A minimal 9P2000.L server exposing a `RepoTree` read-only over TCP, as
+/// an alternative transport to the FUSE frontend in `rtfs.rs`. Only the
+/// subset of the protocol a read-only walk/getattr/read/readdir client
+/// needs is implemented; anything else comes back as `Rlerror`.
+///
+/// `Server` is wrapped in an `Arc` by `serve()` so each connection gets
+/// its own thread: a malformed or malicious message on one connection
+/// can only take down that connection, not every client sharing the
+/// process.
+pub struct Server {
+  tree: RepoTree,
+}
+
+impl Server {
+  pub fn new(tree: RepoTree) -> Self {
+    Self { tree }
+  }
+
+  pub fn serve(self, addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("9P2000.L server listening on {}", addr);
+    let server = std::sync::Arc::new(self);
+    for stream in listener.incoming() {
+      let stream = stream?;
+      let peer = stream.peer_addr().ok();
+      debug!("9P: connection from {:?}", peer);
+      let server = server.clone();
+      thread::spawn(move || {
+        if let Err(e) = server.handle_connection(stream) {
+          error!("9P: connection error: {}", e);
+        }
+      });
+    }
+    Ok(())
+  }
+
+  fn handle_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+    let fids: Mutex<HashMap<u32, Fid>> = Mutex::new(HashMap::new());
+    loop {
+      let msg = match read_message(&mut stream) {
+        Ok(Some(msg)) => msg,
+        Ok(None) => return Ok(()),
+        Err(e) => return Err(e),
+      };
+      let reply = self.dispatch(&msg, &fids);
+      write_message(&mut stream, &reply)?;
+    }
+  }
+
+  fn dispatch(&self, msg: &Message, fids: &Mutex<HashMap<u32, Fid>>) -> Message {
+    let result = match msg.kind {
+      TVERSION => self.r_version(msg),
+      TATTACH => self.r_attach(msg, fids),
+      TWALK => self.r_walk(msg, fids),
+      TLOPEN => self.r_lopen(msg),
+      TGETATTR => self.r_getattr(msg, fids),
+      TREADDIR => self.r_readdir(msg, fids),
+      TREAD => self.r_read(msg, fids),
+      TCLUNK => self.r_clunk(msg, fids),
+      other => {
+        return rlerror(msg.tag, libc::EOPNOTSUPP, &format!("unsupported Tmessage {}", other))
+      }
+    };
+    result.unwrap_or_else(|ParseError| rlerror(msg.tag, libc::EINVAL, "malformed message body"))
+  }
+
+  fn r_version(&self, msg: &Message) -> Result<Message, ParseError> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&MSIZE.to_le_bytes());
+    write_str(&mut body, "9P2000.L");
+    Ok(Message {
+      kind: RVERSION,
+      tag: msg.tag,
+      body,
+    })
+  }
+
+  fn r_attach(&self, msg: &Message, fids: &Mutex<HashMap<u32, Fid>>) -> Result<Message, ParseError> {
+    let mut p = Parser::new(&msg.body);
+    let fid = p.u32()?;
+    let _afid = p.u32()?;
+    let _uname = p.string()?;
+    let _aname = p.string()?;
+
+    let mut table = fids.lock().expect("could not lock fid table");
+    table.insert(
+      fid,
+      Fid {
+        path: "/".to_string(),
+        kind: NodeKind::Directory,
+        dir_buf: None,
+      },
+    );
+    drop(table);
+
+    let qid = Qid::for_path("/", NodeKind::Directory);
+    let mut body = Vec::new();
+    qid.encode(&mut body);
+    Ok(Message {
+      kind: RATTACH,
+      tag: msg.tag,
+      body,
+    })
+  }
+
+  fn r_walk(&self, msg: &Message, fids: &Mutex<HashMap<u32, Fid>>) -> Result<Message, ParseError> {
+    let mut p = Parser::new(&msg.body);
+    let fid = p.u32()?;
+    let newfid = p.u32()?;
+    let nwname = p.u16()?;
+    let mut names = Vec::with_capacity(nwname as usize);
+    for _ in 0..nwname {
+      names.push(p.string()?);
+    }
+
+    let mut table = fids.lock().expect("could not lock fid table");
+    let base_path = match table.get(&fid) {
+      Some(f) => f.path.clone(),
+      None => return Ok(rlerror(msg.tag, libc::EBADF, "unknown fid")),
+    };
+
+    let mut path = base_path;
+    let mut qids = Vec::with_capacity(names.len());
+    for name in &names {
+      path = if path == "/" {
+        format!("/{}", name)
+      } else {
+        format!("{}/{}", path, name)
+      };
+      match self.tree.stat(&path) {
+        Ok(attr) => qids.push(Qid::for_path(&path, attr.kind)),
+        Err(e) => return Ok(rlerror(msg.tag, e.to_errno(), "walk: no such file")),
+      }
+    }
+
+    let final_kind = if names.is_empty() {
+      NodeKind::Directory
+    } else {
+      match self.tree.stat(&path) {
+        Ok(attr) => attr.kind,
+        Err(e) => return Ok(rlerror(msg.tag, e.to_errno(), "walk: stat failed")),
+      }
+    };
+
+    table.insert(
+      newfid,
+      Fid {
+        path,
+        kind: final_kind,
+        dir_buf: None,
+      },
+    );
+    drop(table);
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(qids.len() as u16).to_le_bytes());
+    for qid in &qids {
+      qid.encode(&mut body);
+    }
+    Ok(Message {
+      kind: RWALK,
+      tag: msg.tag,
+      body,
+    })
+  }
+
+  fn r_lopen(&self, msg: &Message) -> Result<Message, ParseError> {
+    let mut p = Parser::new(&msg.body);
+    let _fid = p.u32()?;
+    let _flags = p.u32()?;
+    let qid = Qid::for_path("", NodeKind::File);
+    let mut body = Vec::new();
+    qid.encode(&mut body);
+    body.extend_from_slice(&0u32.to_le_bytes()); // iounit: let the client pick
+    Ok(Message {
+      kind: RLOPEN,
+      tag: msg.tag,
+      body,
+    })
+  }
+
+  fn r_getattr(&self, msg: &Message, fids: &Mutex<HashMap<u32, Fid>>) -> Result<Message, ParseError> {
+    let mut p = Parser::new(&msg.body);
+    let fid = p.u32()?;
+    let _request_mask = p.u64()?;
+
+    let path = {
+      let table = fids.lock().expect("could not lock fid table");
+      match table.get(&fid) {
+        Some(f) => f.path.clone(),
+        None => return Ok(rlerror(msg.tag, libc::EBADF, "unknown fid")),
+      }
+    };
+
+    let attr = match self.tree.stat(&path) {
+      Ok(attr) => attr,
+      Err(e) => return Ok(rlerror(msg.tag, e.to_errno(), "getattr failed")),
+    };
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&GETATTR_BASIC.to_le_bytes());
+    Qid::for_path(&path, attr.kind).encode(&mut body);
+    let mode: u32 = match attr.kind {
+      NodeKind::Directory => 0o040755,
+      NodeKind::File => 0o100644,
+    };
+    body.extend_from_slice(&mode.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes()); // uid
+    body.extend_from_slice(&0u32.to_le_bytes()); // gid
+    body.extend_from_slice(&1u64.to_le_bytes()); // nlink
+    body.extend_from_slice(&0u64.to_le_bytes()); // rdev
+    body.extend_from_slice(&attr.size.to_le_bytes());
+    body.extend_from_slice(&4096u64.to_le_bytes()); // blksize
+    body.extend_from_slice(&0u64.to_le_bytes()); // blocks
+    for _ in 0..8 {
+      body.extend_from_slice(&0u64.to_le_bytes()); // atime/mtime/ctime/btime sec+nsec pairs, zeroed: not worth parsing twice
+    }
+    body.extend_from_slice(&0u64.to_le_bytes()); // gen
+    body.extend_from_slice(&0u64.to_le_bytes()); // data_version
+    Ok(Message {
+      kind: RGETATTR,
+      tag: msg.tag,
+      body,
+    })
+  }
+
+  fn r_readdir(&self, msg: &Message, fids: &Mutex<HashMap<u32, Fid>>) -> Result<Message, ParseError> {
+    let mut p = Parser::new(&msg.body);
+    let fid = p.u32()?;
+    let offset = p.u64()?;
+    let count = p.u32()?;
+
+    let path = {
+      let table = fids.lock().expect("could not lock fid table");
+      match table.get(&fid) {
+        Some(f) if f.kind == NodeKind::Directory => f.path.clone(),
+        Some(_) => return Ok(rlerror(msg.tag, libc::ENOTDIR, "not a directory")),
+        None => return Ok(rlerror(msg.tag, libc::EBADF, "unknown fid")),
+      }
+    };
+
+    let needs_build = {
+      let table = fids.lock().expect("could not lock fid table");
+      table.get(&fid).map_or(true, |f| f.dir_buf.is_none())
+    };
+    if needs_build {
+      let entries = match self.tree.list(&path) {
+        Ok(entries) => entries,
+        Err(e) => return Ok(rlerror(msg.tag, e.to_errno(), "readdir failed")),
+      };
+      let mut buf = Vec::new();
+      for entry in entries.iter() {
+        let child_path = if path == "/" {
+          format!("/{}", entry.name)
+        } else {
+          format!("{}/{}", path, entry.name)
+        };
+        // qid (13) + offset (8) + type (1) + name (2 + len) bytes.
+        let next_offset = (buf.len() + 13 + 8 + 1 + 2 + entry.name.len()) as u64;
+        Qid::for_path(&child_path, entry.kind).encode(&mut buf);
+        buf.extend_from_slice(&next_offset.to_le_bytes()); // byte offset of next entry in dir_buf
+        buf.push(match entry.kind {
+          NodeKind::Directory => libc::DT_DIR,
+          NodeKind::File => libc::DT_REG,
+        });
+        write_str(&mut buf, &entry.name);
+      }
+      let mut table = fids.lock().expect("could not lock fid table");
+      if let Some(f) = table.get_mut(&fid) {
+        f.dir_buf = Some(buf);
+      }
+    }
+
+    let table = fids.lock().expect("could not lock fid table");
+    let dir_buf = table.get(&fid).and_then(|f| f.dir_buf.as_ref());
+    let slice = match dir_buf {
+      Some(buf) if (offset as usize) < buf.len() => {
+        let end = (offset as usize + count as usize).min(buf.len());
+        &buf[offset as usize..end]
+      }
+      _ => &[],
+    };
+    let mut body = Vec::new();
+    body.extend_from_slice(&(slice.len() as u32).to_le_bytes());
+    body.extend_from_slice(slice);
+    Ok(Message {
+      kind: RREADDIR,
+      tag: msg.tag,
+      body,
+    })
+  }
+
+  fn r_read(&self, msg: &Message, fids: &Mutex<HashMap<u32, Fid>>) -> Result<Message, ParseError> {
+    let mut p = Parser::new(&msg.body);
+    let fid = p.u32()?;
+    let offset = p.u64()?;
+    let count = p.u32()?;
+
+    let path = {
+      let table = fids.lock().expect("could not lock fid table");
+      match table.get(&fid) {
+        Some(f) => f.path.clone(),
+        None => return Ok(rlerror(msg.tag, libc::EBADF, "unknown fid")),
+      }
+    };
+
+    let data = match self.tree.read(&path, offset, count) {
+      Ok(data) => data,
+      Err(e) => return Ok(rlerror(msg.tag, e.to_errno(), "read failed")),
+    };
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&data);
+    Ok(Message {
+      kind: RREAD,
+      tag: msg.tag,
+      body,
+    })
+  }
+
+  fn r_clunk(&self, msg: &Message, fids: &Mutex<HashMap<u32, Fid>>) -> Result<Message, ParseError> {
+    let mut p = Parser::new(&msg.body);
+    let fid = p.u32()?;
+    let mut table = fids.lock().expect("could not lock fid table");
+    table.remove(&fid);
+    Ok(Message {
+      kind: RCLUNK,
+      tag: msg.tag,
+      body: Vec::new(),
+    })
+  }
+}
+
+fn rlerror(tag: u16, errno: libc::c_int, context: &str) -> Message {
+  debug!("9P: {} (errno {})", context, errno);
+  let mut body = Vec::new();
+  body.extend_from_slice(&(errno as u32).to_le_bytes());
+  Message {
+    kind: RLERROR,
+    tag,
+    body,
+  }
+}
+
+struct Message {
+  kind: u8,
+  tag: u16,
+  body: Vec<u8>,
+}
+
+/// Reads one 9P message off the wire: a 4-byte little-endian size prefix
+/// (covering the whole message, size field included), a 1-byte type, a
+/// 2-byte tag, then the type-specific body.
+fn read_message(stream: &mut TcpStream) -> io::Result<Option<Message>> {
+  let mut size_buf = [0u8; 4];
+  match stream.read_exact(&mut size_buf) {
+    Ok(()) => {}
+    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(e) => return Err(e),
+  }
+  let size = u32::from_le_bytes(size_buf);
+  if size < 7 || size > MSIZE {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "9P message has an invalid size"));
+  }
+  let mut rest = vec![0u8; size as usize - 4];
+  stream.read_exact(&mut rest)?;
+  let kind = rest[0];
+  let tag = u16::from_le_bytes([rest[1], rest[2]]);
+  let body = rest[3..].to_vec();
+  Ok(Some(Message { kind, tag, body }))
+}
+
+fn write_message(stream: &mut TcpStream, msg: &Message) -> io::Result<()> {
+  let size = 4 + 1 + 2 + msg.body.len() as u32;
+  let mut out = Vec::with_capacity(size as usize);
+  out.extend_from_slice(&size.to_le_bytes());
+  out.push(msg.kind);
+  out.extend_from_slice(&msg.tag.to_le_bytes());
+  out.extend_from_slice(&msg.body);
+  stream.write_all(&out)
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+  buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+  buf.extend_from_slice(s.as_bytes());
+}
+
+/// A 9P request body ended before a field it claimed to carry (e.g. a
+/// `Twalk` whose `nwname` promises more entries than the message
+/// actually has), or its fixed-width fields ran off the end of the
+/// buffer. Callers map this straight to an `Rlerror(EINVAL)` rather than
+/// indexing into the buffer and panicking on attacker-controlled input.
+struct ParseError;
+
+/// Cursor over a message body for reading the little-endian fixed-width
+/// and length-prefixed-string fields 9P messages are built from. Every
+/// accessor bounds-checks against the remaining buffer before reading.
+struct Parser<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    Self { data, pos: 0 }
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u8], ParseError> {
+    if self.data.len() - self.pos < n {
+      return Err(ParseError);
+    }
+    let slice = &self.data[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(slice)
+  }
+
+  fn u16(&mut self) -> Result<u16, ParseError> {
+    let b = self.take(2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, ParseError> {
+    let b = self.take(4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  fn u64(&mut self) -> Result<u64, ParseError> {
+    let b = self.take(8)?;
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(b);
+    Ok(u64::from_le_bytes(bytes))
+  }
+
+  fn string(&mut self) -> Result<String, ParseError> {
+    let len = self.u16()? as usize;
+    let b = self.take(len)?;
+    Ok(String::from_utf8_lossy(b).to_string())
+  }
+}
@@ -1,111 +1,131 @@
 use std::collections::HashMap;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::path::Path;
 use std::sync::Mutex;
 
-use anyhow;
 use fuse_mt::*;
 use libc;
 use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use time::*;
 
-use crate::artifactory::Listing::{Directory, Error, File};
-use crate::artifactory::{Artifactory, Listing};
+use crate::artifactory::{Artifactory, FileInfo};
+use crate::tree::{timestamp_to_timespec, NodeKind, RepoTree};
 
 const TTL: Timespec = Timespec { sec: 10, nsec: 0 };
 const ENOTDIR: libc::c_int = 20;
 const EISDIR: libc::c_int = 21;
 
+const XATTR_NAMES: &[&str] = &[
+  "user.artifactory.sha256",
+  "user.artifactory.sha1",
+  "user.artifactory.md5",
+  "user.artifactory.mime_type",
+  "user.artifactory.created_by",
+  "user.artifactory.modified_by",
+  "user.artifactory.download_uri",
+  "user.artifactory.repo",
+];
+
+fn xattr_value(info: &FileInfo, name: &OsStr) -> Option<String> {
+  match name.to_str()? {
+    "user.artifactory.sha256" => Some(info.checksums.sha256.clone()),
+    "user.artifactory.sha1" => Some(info.checksums.sha1.clone()),
+    "user.artifactory.md5" => Some(info.checksums.md5.clone()),
+    "user.artifactory.mime_type" => Some(info.mime_type.clone()),
+    "user.artifactory.created_by" => Some(info.created_by.clone()),
+    "user.artifactory.modified_by" => Some(info.modified_by.clone()),
+    "user.artifactory.download_uri" => Some(info.download_uri.clone()),
+    "user.artifactory.repo" => Some(info.repo.clone()),
+    _ => None,
+  }
+}
+
+#[derive(Clone)]
+struct ChecksumState {
+  next_offset: u64,
+  sha256: Sha256,
+  sha1: Sha1,
+  expected_sha256: String,
+  expected_sha1: String,
+}
+
+impl ChecksumState {
+  fn new(expected_sha256: String, expected_sha1: String) -> Self {
+    Self {
+      next_offset: 0,
+      sha256: Sha256::new(),
+      sha1: Sha1::new(),
+      expected_sha256,
+      expected_sha1,
+    }
+  }
+
+  fn update(&mut self, offset: u64, data: &[u8]) {
+    self.sha256.input(data);
+    self.sha1.input(data);
+    self.next_offset = offset + data.len() as u64;
+  }
+
+  /// Compares the running digest against the stored checksum, preferring
+  /// SHA-256 and falling back to SHA-1 when Artifactory only reports that.
+  fn verify(&self) -> bool {
+    if !self.expected_sha256.is_empty() {
+      format!("{:x}", self.sha256.clone().result()) == self.expected_sha256
+    } else if !self.expected_sha1.is_empty() {
+      format!("{:x}", self.sha1.clone().result()) == self.expected_sha1
+    } else {
+      true
+    }
+  }
+}
+
 #[derive(Clone)]
 struct FsInfo {
   attr: FileAttr,
   path: String,
+  verify: Option<ChecksumState>,
+  write_buf: Option<Vec<u8>>,
 }
 
 pub struct RtFS {
-  pub rt: Box<Artifactory>,
-  pub repo: String,
+  tree: RepoTree,
   _dir_handles: Mutex<HashMap<u64, FsInfo>>,
   _file_handles: Mutex<HashMap<u64, FsInfo>>,
   _last_dir_handle: Mutex<u64>,
   _last_file_handle: Mutex<u64>,
-  _uris: Mutex<HashMap<String, String>>,
-}
-
-fn timestamp_to_timespec(timestamp: &String) -> anyhow::Result<Timespec> {
-  const FMT: &'static str = "%Y-%m-%dT%H:%M:%S";
-  let parsed = time::strptime(&timestamp, FMT)?;
-  Ok(parsed.to_timespec())
 }
 
 impl RtFS {
   pub fn new(rt: Box<Artifactory>, repo: String) -> Self {
     let mut rng = rand::thread_rng();
     Self {
-      rt: rt,
-      repo: repo,
+      tree: RepoTree::new(rt, repo),
       _dir_handles: Mutex::new(HashMap::new()),
       _file_handles: Mutex::new(HashMap::new()),
-      _uris: Mutex::new(HashMap::new()),
       // totally arbitrary range, I just don't want it too high or too low.
       _last_dir_handle: Mutex::new(rng.gen_range(0xaaaa, std::u64::MAX / 2)),
       _last_file_handle: Mutex::new(rng.gen_range(0xaaaa, std::u64::MAX / 2)),
     }
   }
 
-  fn stat_for_path(&self, path: &String, req: &RequestInfo) -> anyhow::Result<FileAttr> {
-    let path = String::from(match path.as_str() {
-      "/" => "",
-      _ => path,
-    });
-    let path = if path.starts_with("/") {
-      path[1..].to_owned()
-    } else {
-      path.to_owned()
-    };
-    let path = format!("{}/{}", self.repo, path);
-    let listing_result = self.rt.storage(&path);
-    let listing = match listing_result {
-      Ok(lst) => lst,
-      Err(e) => panic!(format!("{:?}", e)),
-    };
-    let kind = match listing {
-      Listing::File(_) => FileType::RegularFile,
-      Listing::Directory(_) => FileType::Directory,
-      _ => FileType::Directory,
-    };
-
-    let mut _uri_registry = self._uris.lock().expect("could not lock _uris");
-    if let Listing::File(f) = &listing {
-      let path = path.trim_start_matches(&self.repo);
-      _uri_registry.insert(path.to_string(), f.uri.clone());
-    }
-
+  fn stat_for_path(&self, path: &String, req: &RequestInfo) -> Result<FileAttr, libc::c_int> {
+    let attr = self.tree.stat(path).map_err(|e| e.to_errno())?;
     let perm = 0o0666;
+    let time_of = |ts: &String| timestamp_to_timespec(ts).map_err(|_| libc::EIO);
 
     Ok(FileAttr {
-      size: match &listing {
-        Listing::File(fi) => fi.size.parse::<u64>().unwrap(),
-        _ => 4096u64,
-      },
+      size: attr.size,
       blocks: 0,
-      atime: match &listing {
-        Listing::File(fi) => timestamp_to_timespec(&fi.last_updated)?,
-        Listing::Directory(di) => timestamp_to_timespec(&di.last_updated)?,
-        _ => Timespec::new(1, 1),
-      },
-      mtime: match &listing {
-        Listing::File(fi) => timestamp_to_timespec(&fi.last_modified)?,
-        Listing::Directory(di) => timestamp_to_timespec(&di.last_modified)?,
-        _ => Timespec::new(1, 1),
-      },
-      ctime: match &listing {
-        Listing::File(fi) => timestamp_to_timespec(&fi.created)?,
-        Listing::Directory(di) => timestamp_to_timespec(&di.created)?,
-        _ => Timespec::new(1, 1),
-      },
+      atime: time_of(&attr.last_updated)?,
+      mtime: time_of(&attr.last_modified)?,
+      ctime: time_of(&attr.created)?,
       crtime: Timespec { sec: 0, nsec: 0 },
-      kind,
+      kind: match attr.kind {
+        NodeKind::File => FileType::RegularFile,
+        NodeKind::Directory => FileType::Directory,
+      },
       perm,
       nlink: 1,
       uid: req.uid,
@@ -129,6 +149,74 @@ impl RtFS {
     *dh
   }
 
+  /// Feeds a just-read chunk into the running checksum for `fh`, provided
+  /// the read is sequential from the start of the file. On reaching EOF
+  /// the accumulated digest is compared against the stored checksum.
+  fn verify_read(&self, fh: u64, offset: u64, data: &[u8]) -> Result<(), libc::c_int> {
+    let mut fh_registry = self._file_handles.lock().expect("Could not lock _file_handles");
+    let fs_info = match fh_registry.get_mut(&fh) {
+      Some(fs_info) => fs_info,
+      None => return Ok(()),
+    };
+    let state = match &mut fs_info.verify {
+      Some(state) => state,
+      None => return Ok(()),
+    };
+
+    if offset != state.next_offset {
+      fs_info.verify = None;
+      return Ok(());
+    }
+
+    state.update(offset, data);
+    let size = fs_info.attr.size;
+    if fs_info.verify.as_ref().map_or(false, |s| s.next_offset >= size) {
+      let state = fs_info.verify.take().expect("checksum state vanished");
+      if state.verify() {
+        debug!("checksum verified for fh {:#x}", fh);
+        Ok(())
+      } else {
+        error!("checksum mismatch for fh {:#x}", fh);
+        Err(libc::EIO)
+      }
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Resolves `path` to its cached `FileInfo`, populating the tree's
+  /// registry via `getattr` first if needed. Directories have no
+  /// `FileInfo`, so this fails with `ENODATA` for them, same as for a
+  /// missing attribute.
+  fn info_for_xattr(&self, req: RequestInfo, path: &Path) -> Result<FileInfo, libc::c_int> {
+    self.getattr(req, path, None)?;
+    let path_str = path.to_str().ok_or(libc::EINVAL)?;
+    self.tree.file_info(path_str).ok_or(libc::ENODATA)
+  }
+
+  /// Deploys the buffered contents of `fh`, if it has any pending writes,
+  /// and invalidates the cached metadata for `path` so the new size and
+  /// checksums are picked up on the next `getattr`/`getxattr`.
+  fn deploy_write_buf(&self, path: &Path, fh: u64) -> Result<(), libc::c_int> {
+    let data = {
+      let fh_registry = self._file_handles.lock().expect("Could not lock _file_handles");
+      fh_registry.get(&fh).and_then(|info| info.write_buf.clone())
+    };
+    let data = match data {
+      Some(data) => data,
+      None => return Ok(()),
+    };
+    let path_str = path.to_string_lossy().to_string();
+    let repo_path = self.tree.repo_path(&path_str);
+    self
+      .tree
+      .rt
+      .put_file(&repo_path, data)
+      .map_err(|e| crate::tree::NodeError::from_reqwest(&e).to_errno())?;
+    self.tree.invalidate(&path_str);
+    Ok(())
+  }
+
   fn get_file_handle(&self, fs_info: &FsInfo) -> u64 {
     let mut fh = self
       ._last_file_handle
@@ -157,7 +245,7 @@ impl FilesystemMT for RtFS {
   fn getattr(&self, req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
     debug!("getattr: {:?}", path);
     let path_str = String::from(path.to_str().unwrap_or("/"));
-    let attr = self.stat_for_path(&path_str, &req).expect("boo");
+    let attr = self.stat_for_path(&path_str, &req)?;
     Ok((TTL, attr))
   }
 
@@ -169,6 +257,8 @@ impl FilesystemMT for RtFS {
         let fs_info = FsInfo {
           path: path.to_string_lossy().to_string(),
           attr: attr,
+          verify: None,
+          write_buf: None,
         };
         let fh = self.get_dir_handle(&fs_info);
         Ok((fh, 0))
@@ -191,40 +281,27 @@ impl FilesystemMT for RtFS {
 
   fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
     debug!("readdir: {:?}", path);
-    let mut entries: Vec<DirectoryEntry> = vec![];
 
     if fh == 0 {
       error!("readdir: missing fh");
       return Err(libc::EINVAL);
     }
 
-    let path_str = format!(
-      "{}/{}",
-      self.repo,
-      String::from(path.to_str().unwrap_or("/"))
-    );
-    let listing = match self.rt.storage(&path_str) {
-      Ok(lst) => lst,
-      Err(_) => return Ok(entries),
-    };
-
-    let listing = match &listing {
-      File(_) => panic!("readdir called for non-directory entry"),
-      Error(_) => return Ok(entries),
-      Directory(d) => d,
-    };
+    let path_str = path.to_string_lossy();
+    let listing = self.tree.list(&path_str).map_err(|e| e.to_errno())?;
 
-    for item in &listing.children {
-      entries.push(DirectoryEntry {
-        name: OsString::from(item.get_name()),
-        kind: if item.folder {
-          FileType::Directory
-        } else {
-          FileType::RegularFile
-        },
-      });
-    }
-    Ok(entries)
+    Ok(
+      listing
+        .into_iter()
+        .map(|entry| DirectoryEntry {
+          name: OsString::from(entry.name),
+          kind: match entry.kind {
+            NodeKind::Directory => FileType::Directory,
+            NodeKind::File => FileType::RegularFile,
+          },
+        })
+        .collect(),
+    )
   }
 
   fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
@@ -232,9 +309,40 @@ impl FilesystemMT for RtFS {
     let (_, attr) = self.getattr(_req, path, None)?;
     match attr.kind {
       FileType::RegularFile => {
+        let path_str = path.to_str().expect("could not convert path to str");
+        let verify = self.tree.file_info(path_str).map(|info| {
+          ChecksumState::new(info.checksums.sha256.clone(), info.checksums.sha1.clone())
+        });
+        let write_buf = if flags & (libc::O_WRONLY | libc::O_RDWR) as u32 != 0 {
+          if flags & libc::O_TRUNC as u32 != 0 || attr.size == 0 {
+            Some(Vec::new())
+          } else if attr.size > std::u32::MAX as u64 {
+            // Not a truncating open: we'd need to seed the write buffer
+            // with the file's full current contents so a partial write
+            // doesn't clobber the rest on deploy, but `tree.read` can't
+            // be asked for more than u32::MAX bytes in one call. Rather
+            // than silently seeding a truncated buffer and deploying a
+            // corrupted file, refuse the open.
+            return Err(libc::EFBIG);
+          } else {
+            // Not a truncating open: seed the buffer with the file's
+            // current contents so a partial/in-place write doesn't
+            // clobber the rest of the file when it's deployed.
+            Some(
+              self
+                .tree
+                .read(path_str, 0, attr.size as u32)
+                .map_err(|e| e.to_errno())?,
+            )
+          }
+        } else {
+          None
+        };
         let fs_info = FsInfo {
           path: path.to_string_lossy().to_string(),
           attr: attr,
+          verify,
+          write_buf,
         };
         let fh = self.get_file_handle(&fs_info);
         Ok((fh, 0))
@@ -253,6 +361,7 @@ impl FilesystemMT for RtFS {
     _flush: bool,
   ) -> ResultEmpty {
     debug!("release: {:?}", path);
+    let deployed = self.deploy_write_buf(path, fh);
     let mut fh_registry = self
       ._file_handles
       .lock()
@@ -260,6 +369,125 @@ impl FilesystemMT for RtFS {
     if fh_registry.contains_key(&fh) {
       fh_registry.remove(&fh);
     }
+    drop(fh_registry);
+    deployed
+  }
+
+  fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
+    debug!("flush: {:?}", path);
+    self.deploy_write_buf(path, fh)
+  }
+
+  fn create(
+    &self,
+    req: RequestInfo,
+    parent: &Path,
+    name: &OsStr,
+    _mode: u32,
+    flags: u32,
+  ) -> ResultCreate {
+    debug!("create: {:?}/{:?}", parent, name);
+    let child = parent.join(name);
+    let child_str = child.to_string_lossy().to_string();
+    let repo_path = self.tree.repo_path(&child_str);
+    self
+      .tree
+      .rt
+      .put_file(&repo_path, Vec::new())
+      .map_err(|e| crate::tree::NodeError::from_reqwest(&e).to_errno())?;
+    self.tree.invalidate(&child_str);
+    self.tree.invalidate(&parent.to_string_lossy());
+
+    let attr = self.stat_for_path(&child_str, &req)?;
+    let fs_info = FsInfo {
+      path: child_str,
+      attr: attr.clone(),
+      verify: None,
+      write_buf: Some(Vec::new()),
+    };
+    let fh = self.get_file_handle(&fs_info);
+    Ok(CreatedEntry {
+      ttl: TTL,
+      attr,
+      fh,
+      flags,
+    })
+  }
+
+  fn write(
+    &self,
+    _req: RequestInfo,
+    path: &Path,
+    fh: u64,
+    offset: u64,
+    data: Vec<u8>,
+    _flags: u32,
+  ) -> ResultWrite {
+    debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
+    let mut fh_registry = self
+      ._file_handles
+      .lock()
+      .expect("Could not lock _file_handles");
+    let fs_info = match fh_registry.get_mut(&fh) {
+      Some(fs_info) => fs_info,
+      None => return Err(libc::EBADF),
+    };
+    let buf = match &mut fs_info.write_buf {
+      Some(buf) => buf,
+      None => return Err(libc::EBADF),
+    };
+    let end = offset as usize + data.len();
+    if buf.len() < end {
+      buf.resize(end, 0);
+    }
+    buf[offset as usize..end].copy_from_slice(&data);
+    Ok(data.len() as u32)
+  }
+
+  fn mkdir(&self, req: RequestInfo, parent: &Path, name: &OsStr, _mode: u32) -> ResultEntry {
+    debug!("mkdir: {:?}/{:?}", parent, name);
+    let child = parent.join(name);
+    let child_str = child.to_string_lossy().to_string();
+    let marker_path = self.tree.repo_path(&format!("{}/.marker", child_str));
+    self
+      .tree
+      .rt
+      .put_file(&marker_path, Vec::new())
+      .map_err(|e| crate::tree::NodeError::from_reqwest(&e).to_errno())?;
+    self.tree.invalidate(&child_str);
+    self.tree.invalidate(&parent.to_string_lossy());
+
+    let attr = self.stat_for_path(&child_str, &req)?;
+    Ok((TTL, attr))
+  }
+
+  fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+    debug!("unlink: {:?}/{:?}", parent, name);
+    let child = parent.join(name);
+    let child_str = child.to_string_lossy().to_string();
+    let repo_path = self.tree.repo_path(&child_str);
+    self
+      .tree
+      .rt
+      .delete_path(&repo_path)
+      .map_err(|e| crate::tree::NodeError::from_reqwest(&e).to_errno())?;
+    self.tree.invalidate(&child_str);
+    self.tree.invalidate(&parent.to_string_lossy());
+    Ok(())
+  }
+
+  fn rmdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+    debug!("rmdir: {:?}/{:?}", parent, name);
+    let child = parent.join(name);
+    let child_str = child.to_string_lossy().to_string();
+    let repo_path = self.tree.repo_path(&child_str);
+    self
+      .tree
+      .rt
+      .delete_path(&repo_path)
+      .map_err(|e| crate::tree::NodeError::from_reqwest(&e).to_errno())?;
+    self.tree.invalidate(&child_str);
+    self.tree.invalidate(&parent.to_string_lossy());
     Ok(())
   }
 
@@ -267,28 +495,56 @@ impl FilesystemMT for RtFS {
     &self,
     _req: RequestInfo,
     path: &Path,
-    _fh: u64,
+    fh: u64,
     offset: u64,
     size: u32,
     result: impl FnOnce(Result<&[u8], libc::c_int>),
   ) {
     debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
-    let mut data = Vec::<u8>::with_capacity(size as usize);
-    unsafe { data.set_len(size as usize) };
 
-    let _uri_registry = self._uris.lock().expect("could not lock _uris");
     let path_str = path.to_str().expect("could not convert path to str");
-    match _uri_registry.get(path_str) {
-      Some(uri) => {
-        debug!("uri for file: {}", uri);
-        self.rt.read_file(&uri, offset, size, &mut data).expect("could not read file");
-      },
-      None => {
-        println!("{:?}", _uri_registry);
-        panic!("at the disco");
-      }
+    let data = match self.tree.read(path_str, offset, size) {
+      Ok(data) => data,
+      Err(e) => return result(Err(e.to_errno())),
+    };
+
+    if let Err(errno) = self.verify_read(fh, offset, &data) {
+      return result(Err(errno));
     }
 
     result(Ok(&data));
   }
+
+  fn getxattr(&self, req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
+    debug!("getxattr: {:?} {:?}", path, name);
+    let info = self.info_for_xattr(req, path)?;
+    let value = match xattr_value(&info, name) {
+      Some(value) => value,
+      None => return Err(libc::ENODATA),
+    };
+    if size == 0 {
+      return Ok(Xattr::Size(value.len() as u32));
+    }
+    if value.len() > size as usize {
+      return Err(libc::ERANGE);
+    }
+    Ok(Xattr::Data(value.into_bytes()))
+  }
+
+  fn listxattr(&self, req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+    debug!("listxattr: {:?}", path);
+    self.info_for_xattr(req, path)?;
+    let mut names = String::new();
+    for name in XATTR_NAMES {
+      names.push_str(name);
+      names.push('\0');
+    }
+    if size == 0 {
+      return Ok(Xattr::Size(names.len() as u32));
+    }
+    if names.len() > size as usize {
+      return Err(libc::ERANGE);
+    }
+    Ok(Xattr::Data(names.into_bytes()))
+  }
 }
@@ -0,0 +1,117 @@
+use std::io::{self, Write};
+
+const BLOCK_SIZE: usize = 512;
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+
+/// Writes one POSIX ustar header block for `name`, as given: callers pass
+/// directory names with the conventional trailing `/` already on them,
+/// since this function does not add one. Callers write `size` bytes of
+/// their own and then call `write_padding` to round up to the next
+/// block, rather than this function buffering the body itself.
+pub fn write_header(
+  out: &mut impl Write,
+  name: &str,
+  size: u64,
+  mtime: i64,
+  mode: u32,
+  is_dir: bool,
+) -> io::Result<()> {
+  let mut header = [0u8; BLOCK_SIZE];
+  let (prefix, short_name) = split_name(name);
+  write_field(&mut header[0..NAME_LEN], short_name.as_bytes());
+  write_field(&mut header[345..500], prefix.as_bytes());
+  write_octal(&mut header[100..108], mode as u64);
+  write_octal(&mut header[108..116], 0); // uid
+  write_octal(&mut header[116..124], 0); // gid
+  write_octal(&mut header[124..136], clamp_size(if is_dir { 0 } else { size }));
+  write_octal(&mut header[136..148], mtime.max(0) as u64);
+  header[156] = if is_dir { b'5' } else { b'0' };
+  write_field(&mut header[257..263], b"ustar");
+  header[263] = b'0';
+  header[264] = b'0';
+
+  for b in header[148..156].iter_mut() {
+    *b = b' ';
+  }
+  let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+  write_octal(&mut header[148..154], checksum as u64);
+  header[154] = 0;
+  header[155] = b' ';
+
+  out.write_all(&header)
+}
+
+/// The ustar size field is 12 bytes including its trailing NUL/space, so
+/// it holds at most 11 octal digits - about 8 GiB. Artifactory routinely
+/// hosts artifacts past that; clamp and warn rather than let the value
+/// overflow the field and corrupt every header byte after it, mirroring
+/// `split_name`'s fallback below.
+const MAX_SIZE: u64 = (1 << (3 * (12 - 1))) - 1;
+
+fn clamp_size(size: u64) -> u64 {
+  if size > MAX_SIZE {
+    warn!(
+      "tar: entry size {} exceeds the ustar size field's {}-byte limit; archive entry will be truncated",
+      size, MAX_SIZE
+    );
+    return MAX_SIZE;
+  }
+  size
+}
+
+/// Splits `name` into a ustar `(prefix, name)` pair so paths longer than
+/// the 100-byte `name` field don't silently truncate and collide (the
+/// Maven `groupId/artifactId/version/...` layout this export walks
+/// routinely exceeds 100 bytes). Splits at the rightmost `/` that leaves
+/// both halves within their field limits, per the ustar prefix extension
+/// (header bytes 345..500), joined back as `prefix/name` by readers that
+/// support it. Falls back to a truncated `name` with no prefix - and logs
+/// a warning, since that can collide - only if no such split exists.
+fn split_name(name: &str) -> (&str, &str) {
+  if name.len() <= NAME_LEN {
+    return ("", name);
+  }
+  for (i, _) in name.char_indices().filter(|&(_, c)| c == '/').rev() {
+    let prefix = &name[..i];
+    let rest = &name[i + 1..];
+    if prefix.len() <= PREFIX_LEN && rest.len() <= NAME_LEN {
+      return (prefix, rest);
+    }
+  }
+  warn!(
+    "tar: path {:?} has no {}/{} byte split point; truncating name, entry may collide with another",
+    name, PREFIX_LEN, NAME_LEN
+  );
+  let mut start = name.len().saturating_sub(NAME_LEN);
+  while start < name.len() && !name.is_char_boundary(start) {
+    start += 1;
+  }
+  ("", &name[start..])
+}
+
+fn write_field(dst: &mut [u8], src: &[u8]) {
+  let n = src.len().min(dst.len());
+  dst[..n].copy_from_slice(&src[..n]);
+}
+
+fn write_octal(dst: &mut [u8], value: u64) {
+  let width = dst.len() - 1;
+  let digits = format!("{:0width$o}", value, width = width);
+  write_field(dst, digits.as_bytes());
+}
+
+/// Pads a just-written `n`-byte file body up to the next 512-byte
+/// boundary, the record size every tar entry is aligned to.
+pub fn write_padding(out: &mut impl Write, n: u64) -> io::Result<()> {
+  let rem = (BLOCK_SIZE as u64 - (n % BLOCK_SIZE as u64)) % BLOCK_SIZE as u64;
+  if rem > 0 {
+    out.write_all(&vec![0u8; rem as usize])?;
+  }
+  Ok(())
+}
+
+/// Writes the two all-zero end-of-archive blocks tar readers expect.
+pub fn write_end(out: &mut impl Write) -> io::Result<()> {
+  out.write_all(&[0u8; BLOCK_SIZE * 2])
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow;
+use libc;
+use reqwest;
+use time::Timespec;
+
+use crate::artifactory::{Artifactory, FileInfo, Listing, RtErrors};
+use crate::cache::BlockCache;
+
+/// Parses an Artifactory timestamp (`created`/`lastModified`/`lastUpdated`)
+/// into a `Timespec`, shared by every frontend that needs to turn one into
+/// FUSE `atime`/`mtime` fields or a tar header's mtime.
+pub(crate) fn timestamp_to_timespec(timestamp: &str) -> anyhow::Result<Timespec> {
+  const FMT: &'static str = "%Y-%m-%dT%H:%M:%S";
+  let parsed = time::strptime(timestamp, FMT)?;
+  Ok(parsed.to_timespec())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+  File,
+  Directory,
+}
+
+pub struct NodeAttr {
+  pub kind: NodeKind,
+  pub size: u64,
+  pub created: String,
+  pub last_modified: String,
+  pub last_updated: String,
+}
+
+pub struct NodeEntry {
+  pub name: String,
+  pub kind: NodeKind,
+}
+
+/// Transport-agnostic outcome of a failed lookup/read, translated to a
+/// `libc` errno by whichever frontend (FUSE or 9P) is serving the request.
+pub enum NodeError {
+  NotFound,
+  NotADirectory,
+  PermissionDenied,
+  Io,
+}
+
+impl NodeError {
+  pub fn to_errno(&self) -> libc::c_int {
+    match self {
+      NodeError::NotFound => libc::ENOENT,
+      NodeError::NotADirectory => libc::ENOTDIR,
+      NodeError::PermissionDenied => libc::EACCES,
+      NodeError::Io => libc::EIO,
+    }
+  }
+
+  fn from_transport(err: &anyhow::Error) -> Self {
+    match err.downcast_ref::<reqwest::Error>() {
+      Some(re) => NodeError::from_reqwest(re),
+      None => NodeError::Io,
+    }
+  }
+
+  /// Maps a `reqwest::Error` straight to a `NodeError`, for callers (like
+  /// `Artifactory::put_file`/`delete_path`) that don't go through the
+  /// `anyhow`-wrapped `storage`/`read_file` path.
+  pub(crate) fn from_reqwest(re: &reqwest::Error) -> Self {
+    if re.is_timeout() || re.is_connect() {
+      return NodeError::Io;
+    }
+    match re.status().map(|s| s.as_u16()) {
+      Some(404) => NodeError::NotFound,
+      Some(401) | Some(403) => NodeError::PermissionDenied,
+      _ => NodeError::Io,
+    }
+  }
+
+  fn from_listing(errs: &RtErrors) -> Self {
+    match errs.errors.first().map(|e| e.status) {
+      Some(404) => NodeError::NotFound,
+      Some(401) | Some(403) => NodeError::PermissionDenied,
+      _ => NodeError::Io,
+    }
+  }
+}
+
+/// Shared, transport-agnostic view onto an Artifactory repo: path
+/// resolution, directory listing, and cached/ranged file reads. Both the
+/// FUSE frontend (`rtfs::RtFS`) and the 9P frontend (`ninep::Server`) sit
+/// on top of this, so neither has to duplicate the repo-prefixing or the
+/// download-uri bookkeeping.
+pub struct RepoTree {
+  pub rt: Box<Artifactory>,
+  pub repo: String,
+  uris: Mutex<HashMap<String, FileInfo>>,
+  cache: BlockCache,
+}
+
+impl RepoTree {
+  pub fn new(rt: Box<Artifactory>, repo: String) -> Self {
+    Self {
+      rt,
+      repo,
+      uris: Mutex::new(HashMap::new()),
+      cache: BlockCache::new(),
+    }
+  }
+
+  /// Joins a tree-relative path onto `self.repo`, the form Artifactory's
+  /// API expects (e.g. `"my-repo/some/file"`).
+  pub fn repo_path(&self, path: &str) -> String {
+    let trimmed = match path {
+      "/" => "",
+      _ => path.trim_start_matches('/'),
+    };
+    format!("{}/{}", self.repo, trimmed)
+  }
+
+  /// Drops any cached `FileInfo` for `path`, e.g. after a mutation makes
+  /// it stale.
+  pub fn invalidate(&self, path: &str) {
+    let mut uris = self.uris.lock().expect("could not lock uris");
+    uris.remove(path);
+  }
+
+  /// Returns the cached `FileInfo` for `path`, if a prior `stat`/`read`
+  /// has populated it. Frontends use this for checksum/xattr bookkeeping
+  /// they want to keep outside of this shared core.
+  pub fn file_info(&self, path: &str) -> Option<FileInfo> {
+    let uris = self.uris.lock().expect("could not lock uris");
+    uris.get(path).cloned()
+  }
+
+  fn fetch(&self, path: &str) -> Result<Listing, NodeError> {
+    let full_path = self.repo_path(path);
+    let listing = self
+      .rt
+      .storage(&full_path)
+      .map_err(|e| NodeError::from_transport(&e))?;
+    if let Listing::Error(errs) = &listing {
+      return Err(NodeError::from_listing(errs));
+    }
+    if let Listing::File(f) = &listing {
+      let mut uris = self.uris.lock().expect("could not lock uris");
+      uris.insert(path.to_string(), f.clone());
+    }
+    Ok(listing)
+  }
+
+  pub fn stat(&self, path: &str) -> Result<NodeAttr, NodeError> {
+    let listing = self.fetch(path)?;
+    Ok(match &listing {
+      Listing::File(fi) => NodeAttr {
+        kind: NodeKind::File,
+        size: fi.size.parse::<u64>().unwrap_or(0),
+        created: fi.created.clone(),
+        last_modified: fi.last_modified.clone(),
+        last_updated: fi.last_updated.clone(),
+      },
+      _ => NodeAttr {
+        kind: NodeKind::Directory,
+        size: 4096,
+        created: String::new(),
+        last_modified: String::new(),
+        last_updated: String::new(),
+      },
+    })
+  }
+
+  pub fn list(&self, path: &str) -> Result<Vec<NodeEntry>, NodeError> {
+    let listing = self.fetch(path)?;
+    let dir = match &listing {
+      Listing::Directory(d) => d,
+      _ => return Err(NodeError::NotADirectory),
+    };
+    Ok(
+      dir
+        .children
+        .iter()
+        .map(|item| NodeEntry {
+          name: item.get_name().to_string(),
+          kind: if item.folder {
+            NodeKind::Directory
+          } else {
+            NodeKind::File
+          },
+        })
+        .collect(),
+    )
+  }
+
+  /// Recursively walks `path` depth-first, returning it and every
+  /// descendant paired with its attributes. Used by the tar export to
+  /// build its listing up front; a future writable/caching feature that
+  /// needs a full subtree view can reuse it the same way.
+  pub fn walk(&self, path: &str) -> Result<Vec<(String, NodeAttr)>, NodeError> {
+    let attr = self.stat(path)?;
+    let kind = attr.kind;
+    let mut out = vec![(path.to_string(), attr)];
+    if kind == NodeKind::Directory {
+      for entry in self.list(path)? {
+        let child = if path == "/" {
+          format!("/{}", entry.name)
+        } else {
+          format!("{}/{}", path, entry.name)
+        };
+        out.extend(self.walk(&child)?);
+      }
+    }
+    Ok(out)
+  }
+
+  /// Serves `offset..offset+size` of the file at `path` through the
+  /// block cache, resolving its download URI first if this is the first
+  /// read of the file.
+  pub fn read(&self, path: &str, offset: u64, size: u32) -> Result<Vec<u8>, NodeError> {
+    let info = match self.file_info(path) {
+      Some(info) => info,
+      None => {
+        self.fetch(path)?;
+        self.file_info(path).ok_or(NodeError::NotFound)?
+      }
+    };
+    let fetch = |block_offset: u64, block_size: u32| -> anyhow::Result<Vec<u8>> {
+      let mut block = Vec::<u8>::with_capacity(block_size as usize);
+      self
+        .rt
+        .read_file(&info.download_uri, block_offset, block_size, &mut block)?;
+      Ok(block)
+    };
+    self
+      .cache
+      .read(&info.download_uri, &info.last_updated, offset, size, &fetch)
+      .map_err(|e| NodeError::from_transport(&e))
+  }
+}